@@ -1,13 +1,11 @@
-extern crate cmake;
-extern crate gcc;
+extern crate cc;
 extern crate pkg_config;
+#[cfg(target_env = "msvc")]
+extern crate vcpkg;
 
 use std::env;
-use std::ffi::OsString;
-use std::fs::{self, File};
-use std::io::prelude::*;
+use std::fs;
 use std::path::{Path, PathBuf};
-use std::process::Command;
 
 macro_rules! t {
     ($e:expr) => (match $e{
@@ -17,154 +15,196 @@ macro_rules! t {
 }
 
 fn main() {
+    let vendored = env::var("CARGO_FEATURE_VENDORED").is_ok();
+    let zlib_ng_compat = env::var("CARGO_FEATURE_ZLIB_NG_COMPAT").is_ok();
+
+    // `vendored` means "always build from source", so don't even look for a
+    // vcpkg-installed copy in that case. Same goes for `zlib-ng-compat`: a
+    // vcpkg-installed libgit2 won't be linked against zlib-ng either, so it's
+    // just as unsuitable here as the plain pkg-config probe below.
+    if !vendored && !zlib_ng_compat && try_vcpkg() {
+        return
+    }
+
+    let target = env::var("TARGET").unwrap();
+    let windows = target.contains("windows");
+    let msvc = target.contains("msvc");
+    let apple = target.contains("apple");
+
     let https = env::var("CARGO_FEATURE_HTTPS").is_ok();
+    let https_winhttp = windows && env::var("CARGO_FEATURE_HTTPS_WINHTTP").is_ok();
     let ssh = env::var("CARGO_FEATURE_SSH").is_ok();
-    if ssh {
-        register_dep("SSH2");
-    }
-    if https {
-        register_dep("OPENSSL");
-    }
-    let has_pkgconfig = Command::new("pkg-config").output().is_ok();
 
     if env::var("LIBGIT2_SYS_USE_PKG_CONFIG").is_ok() {
+        if zlib_ng_compat {
+            panic!("LIBGIT2_SYS_USE_PKG_CONFIG is not supported with the \
+                    zlib-ng-compat feature since the system libgit2 won't \
+                    be linked against zlib-ng");
+        }
         if pkg_config::find_library("libgit2").is_ok() {
             return
         }
     }
 
-    let target = env::var("TARGET").unwrap();
-    let host = env::var("HOST").unwrap();
-    let windows = target.contains("windows");
-    let msvc = target.contains("msvc");
-    let mut cfg = cmake::Config::new("libgit2");
-
-    if msvc {
-        // libgit2 passes the /GL flag to enable whole program optimization, but
-        // this requires that the /LTCG flag is passed to the linker later on,
-        // and currently the compiler does not do that, so we disable whole
-        // program optimization entirely.
-        cfg.cflag("/GL-");
-
-        // Currently liblibc links to msvcrt which apparently is a dynamic CRT,
-        // so we need to turn this off to get it to link right.
-        cfg.define("STATIC_CRT", "OFF");
-    }
-
-    // libgit2 uses pkg-config to discover libssh2, but this doesn't work on
-    // windows as libssh2 doesn't come with a libssh2.pc file in that install
-    // (or when pkg-config isn't found). As a result we just manually turn on
-    // SSH support in libgit2 (a little jankily) here...
-    if ssh && (windows || !has_pkgconfig) {
-        if let Ok(libssh2_include) = env::var("DEP_SSH2_INCLUDE") {
-            if msvc {
-                cfg.cflag(format!("/I{}", libssh2_include))
-                   .cflag("/DGIT_SSH");
-            } else {
-                cfg.cflag(format!("-I{}", libssh2_include))
-                   .cflag("-DGIT_SSH");
+    if !vendored && !zlib_ng_compat {
+        if let Ok(lib) = pkg_config::Config::new()
+                                           .atleast_version("0.25.1")
+                                           .probe("libgit2") {
+            for include in &lib.include_paths {
+                println!("cargo:include={}", include.display());
             }
+            return
         }
     }
 
-    // When cross-compiling, we're pretty unlikely to find a `dlltool` binary
-    // lying around, so try to find another if it exists
-    if windows && !host.contains("windows") {
-        let c_compiler = gcc::Config::new().cargo_metadata(false)
-                                           .get_compiler();
-        let exe = c_compiler.path();
-        let path = env::var_os("PATH").unwrap_or(OsString::new());
-        let exe = env::split_paths(&path)
-                      .map(|p| p.join(&exe))
-                      .find(|p| p.exists());
-        if let Some(exe) = exe {
-            if let Some(name) = exe.file_name().and_then(|e| e.to_str()) {
-                let name = name.replace("gcc", "dlltool");
-                let dlltool = exe.with_file_name(name);
-                cfg.define("DLLTOOL", &dlltool);
-            }
+    // No cmake (and so no toolchain-detection magic, and no `dlltool`
+    // guessing for cross-compiles) required any more: we drive the compiler
+    // directly through `cc`, the same way libssh2-sys and curl-sys do.
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let build = out_dir.join("build");
+    let _ = fs::remove_dir_all(&build);
+    t!(fs::create_dir_all(&build));
+
+    let root = Path::new("libgit2");
+    let include = root.join("include");
+
+    let mut cfg = cc::Build::new();
+    cfg.include(&include)
+       .include(root.join("src"))
+       .include(root.join("deps/http-parser"))
+       .include(root.join("deps/sha1dc"))
+       .include(root.join("deps/regex"))
+       .warnings(false)
+       .out_dir(&build)
+       .target(&target)
+       .host(&env::var("HOST").unwrap());
+
+    // Sources shared across all platforms: the core object/odb/refs code,
+    // the git/http(s)/smart/ssh transport implementations, and libgit2's
+    // bundled http-parser.
+    add_c_files(&mut cfg, &root.join("src"));
+    add_c_files(&mut cfg, &root.join("src/transports"));
+    add_c_files(&mut cfg, &root.join("deps/http-parser"));
+
+    if windows {
+        add_c_files(&mut cfg, &root.join("src/win32"));
+        cfg.define("GIT_WIN32", None)
+           .define("GIT_THREADS", None)
+           .define("STRSAFE_NO_DEPRECATE", None)
+           .define("_WIN32_WINNT", Some("0x0600"));
+        if msvc {
+            cfg.flag("/GL-");
         }
-    }
-
-    if ssh {
-        cfg.register_dep("SSH2");
     } else {
-        cfg.define("USE_SSH", "OFF");
+        add_c_files(&mut cfg, &root.join("src/unix"));
+        cfg.define("GIT_THREADS", None)
+           .flag("-fvisibility=hidden");
     }
-    if https {
-        cfg.register_dep("OPENSSL");
-    } else {
-        cfg.define("USE_OPENSSL", "OFF");
+
+    if apple {
+        cfg.define("GIT_USE_ICONV", None);
+        println!("cargo:rustc-link-lib=iconv");
+        println!("cargo:rustc-link-lib=framework=Security");
+        println!("cargo:rustc-link-lib=framework=CoreFoundation");
     }
 
-    let _ = fs::remove_dir_all(env::var("OUT_DIR").unwrap());
-    t!(fs::create_dir_all(env::var("OUT_DIR").unwrap()));
-
-    let dst = cfg.define("BUILD_SHARED_LIBS", "OFF")
-                 .define("BUILD_CLAR", "OFF")
-                 .define("CURL", "OFF")
-                 .register_dep("Z")
-                 .build();
-
-    // Make sure libssh2 was detected on unix systems, because it definitely
-    // should have been!
-    if ssh && !msvc {
-        let flags = dst.join("build/CMakeFiles/git2.dir/flags.make");
-        let mut contents = String::new();
-        t!(t!(File::open(flags)).read_to_string(&mut contents));
-        if !contents.contains("-DGIT_SSH") {
-            panic!("libgit2 failed to find libssh2, and SSH support is required");
+    // `src/xdiff` is the bundled diff/merge engine, required unconditionally.
+    // For the SHA1 and regex backends we default to libgit2's bundled
+    // implementations rather than reverse-engineering what the system
+    // provides: `deps/sha1dc` is the collision-detecting SHA1 backend that
+    // `GIT_SHA1_COLLISIONDETECT` selects, and `deps/regex` is the bundled
+    // regex implementation that `GIT_REGEX_BUILTIN` selects.
+    add_c_files(&mut cfg, &root.join("src/xdiff"));
+    add_c_files(&mut cfg, &root.join("src/hash"));
+    add_c_files(&mut cfg, &root.join("deps/sha1dc"));
+    add_c_files(&mut cfg, &root.join("deps/regex"));
+    cfg.define("GIT_SHA1_COLLISIONDETECT", None)
+       .define("GIT_REGEX_BUILTIN", None);
+
+    if ssh {
+        cfg.define("GIT_SSH", None);
+        if let Ok(libssh2_include) = env::var("DEP_SSH2_INCLUDE") {
+            cfg.include(libssh2_include);
         }
     }
 
-    if target.contains("windows") {
+    if https_winhttp {
+        // Talk HTTPS through the native WinHTTP/Schannel transport, so
+        // libgit2 itself is not compiled against OpenSSL on Windows. The
+        // `https-winhttp` feature still enables `https`, so the openssl-sys
+        // crate is still built.
+        cfg.define("GIT_HTTPS", None)
+           .define("GIT_WINHTTP", None);
         println!("cargo:rustc-link-lib=winhttp");
         println!("cargo:rustc-link-lib=rpcrt4");
         println!("cargo:rustc-link-lib=ole32");
         println!("cargo:rustc-link-lib=crypt32");
-        println!("cargo:rustc-link-lib=static=git2");
-        println!("cargo:rustc-link-search=native={}/lib", dst.display());
-        return
+    } else if https {
+        cfg.define("GIT_HTTPS", None)
+           .define("GIT_OPENSSL", None);
+        if let Ok(openssl_include) = env::var("DEP_OPENSSL_INCLUDE") {
+            cfg.include(openssl_include);
+        }
     }
 
-    // libgit2 requires the http_parser library for the HTTP transport to be
-    // implemented, and it will attempt to use the system http_parser if it's
-    // available. Detect this situation and report using the system http parser
-    // the same way in this situation.
-    //
-    // Note that other dependencies of libgit2 like openssl, libz, and libssh2
-    // are tracked via crates instead of this. Ideally this should be a crate as
-    // well.
-    let pkgconfig_file = dst.join("lib/pkgconfig/libgit2.pc");
-    if let Ok(mut f) = File::open(&pkgconfig_file) {
-        let mut contents = String::new();
-        t!(f.read_to_string(&mut contents));
-        if contents.contains("-lhttp_parser") {
-            println!("cargo:rustc-link-lib=http_parser");
-        }
+    // Whether libz-sys was built plain or with its `zlib-ng` feature (which
+    // our `zlib-ng-compat` feature turns on), it exposes the same zlib API and advertises its headers
+    // through `DEP_Z_INCLUDE`, so there's nothing zlib-ng-specific to do
+    // here beyond picking up that include path.
+    if let Ok(include) = env::var("DEP_Z_INCLUDE") {
+        cfg.include(include);
     }
 
-    println!("cargo:rustc-link-lib=static=git2");
-    println!("cargo:rustc-link-search=native={}", dst.join("lib").display());
-    if target.contains("apple") {
-        println!("cargo:rustc-link-lib=iconv");
-        println!("cargo:rustc-link-lib=framework=Security");
-        println!("cargo:rustc-link-lib=framework=CoreFoundation");
+    cfg.compile("git2");
+
+    // Let downstream -sys crates that want to build C shims or run bindgen
+    // against libgit2's headers find them without re-discovering our build
+    // directory themselves. Cargo keeps only the last value printed for a
+    // metadata key, so the libgit2, http-parser and libssh2 include
+    // directories go out as one list of absolute paths, joined the way
+    // `env::join_paths` joins them.
+    println!("cargo:root={}", out_dir.display());
+    let manifest_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let mut includes = vec![manifest_dir.join(&include),
+                            manifest_dir.join(root.join("deps/http-parser"))];
+    if let Ok(libssh2_include) = env::var("DEP_SSH2_INCLUDE") {
+        includes.push(PathBuf::from(libssh2_include));
     }
+    println!("cargo:include={}", t!(env::join_paths(includes)).to_string_lossy());
 }
 
-fn register_dep(dep: &str) {
-    match env::var(&format!("DEP_{}_ROOT", dep)) {
-        Ok(s) => {
-            prepend("PKG_CONFIG_PATH", Path::new(&s).join("lib/pkgconfig"));
+// Attempt to find libgit2 (and the dependencies it was built against) via
+// vcpkg. Only attempted on MSVC, where vcpkg is the common way for users to
+// manage native dependencies and avoid compiling libgit2's C sources at all.
+#[cfg(target_env = "msvc")]
+fn try_vcpkg() -> bool {
+    match vcpkg::Config::new()
+                        .emit_includes(true)
+                        .find_package("libgit2") {
+        Ok(_) => true,
+        Err(e) => {
+            println!("Could not find libgit2 via vcpkg: {}", e);
+            false
         }
-        Err(..) => {}
     }
 }
 
-fn prepend(var: &str, val: PathBuf) {
-    let prefix = env::var(var).unwrap_or(String::new());
-    let mut v = vec![val];
-    v.extend(env::split_paths(&prefix));
-    env::set_var(var, &env::join_paths(v).unwrap());
+#[cfg(not(target_env = "msvc"))]
+fn try_vcpkg() -> bool {
+    false
+}
+
+// Add every `*.c` file directly inside `dir` (non-recursive, matching how
+// libgit2's own CMakeLists.txt globs each source directory) to `cfg`.
+fn add_c_files(cfg: &mut cc::Build, dir: &Path) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => panic!("failed to read {}: {}", dir.display(), e),
+    };
+    for entry in entries {
+        let path = t!(entry).path();
+        if path.extension().and_then(|e| e.to_str()) == Some("c") {
+            cfg.file(path);
+        }
+    }
 }